@@ -4,15 +4,23 @@ use std::io::prelude::*;
 use std::net::{Ipv4Addr};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 mod tcp;
 
+pub use tcp::Available;
+
 const SENDQUEUE_SIZE: usize = 1024;
 
+/// The address assigned to our end of the tun device (matches the usual `ip addr add
+/// 192.168.0.1/24 dev tun0` setup used to run this stack), used as the source address
+/// for actively-opened connections.
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
-struct Quad {
-	source: (Ipv4Addr, u16),
-	destination: (Ipv4Addr, u16),
+pub struct Quad {
+	pub source: (Ipv4Addr, u16),
+	pub destination: (Ipv4Addr, u16),
 }
 
 #[derive(Default)]
@@ -20,6 +28,7 @@ struct Foobar {
 	manager: Mutex<ConnectionManager>,
 	pending_var: Condvar,
 	recieving_var: Condvar,
+	poll_var: Condvar,
 }
 
 type InterfaceHandle = Arc<Foobar>;
@@ -46,6 +55,22 @@ struct ConnectionManager {
 	terminate: bool,
 	connections: HashMap<Quad, tcp::Connection>,
 	pending: HashMap<u16, VecDeque<Quad>>,
+	/// Quads whose connection was torn down by a RST rather than a normal close, kept
+	/// around just long enough for the next `TcpStream` operation to report
+	/// `ConnectionRefused` instead of the generic `ConnectionAborted`.
+	refused: std::collections::HashSet<Quad>,
+}
+
+/// The error to report when a `TcpStream` operation finds its connection gone: a
+/// distinguishable `ConnectionRefused` if a RST took it down, otherwise the generic
+/// `ConnectionAborted`.
+fn terminated_error(cm: &mut ConnectionManager, quad: &Quad) -> io::Error {
+	let kind = if cm.refused.remove(quad) {
+		io::ErrorKind::ConnectionRefused
+	} else {
+		io::ErrorKind::ConnectionAborted
+	};
+	io::Error::new(kind, "stream was terminated unexpectedly")
 }
 
 fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
@@ -63,8 +88,19 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
 		assert_ne!(n, -1);
 		if n == 0 {
 			let mut cmg = ih.manager.lock().unwrap();
-			for connection in cmg.connections.values_mut() {
+			let mut done = Vec::new();
+			for (&quad, connection) in cmg.connections.iter_mut() {
 				connection.on_tick(&mut nic)?;
+				if connection.is_done() {
+					done.push(quad);
+				}
+			}
+			for quad in done {
+				if let Some(connection) = cmg.connections.remove(&quad) {
+					if connection.refused {
+						cmg.refused.insert(quad);
+					}
+				}
 			}
 			continue;
 		}
@@ -101,6 +137,13 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
 									&buf[datai..nbytes],
 								)?;
 
+								if c.get().is_done() {
+									let (_, connection) = c.remove_entry();
+									if connection.refused {
+										cm.refused.insert(q);
+									}
+								}
+
 								drop(cmg);
 								if a.contains(tcp::Available::READ) {
 									ih.recieving_var.notify_all();
@@ -108,6 +151,9 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
 								if a.contains(tcp::Available::WRITE) {
 									ih.pending_var.notify_all();
 								}
+								if !a.is_empty() {
+									ih.poll_var.notify_all();
+								}
 							}
 
 							Entry::Vacant(e) => {
@@ -180,6 +226,84 @@ impl Interface {
 			h: self.ih.as_mut().unwrap().clone(),
 		})
 	}
+
+	/// Block until at least one of `interest` has the `Available` flags it asked for, or
+	/// `timeout` elapses. Readiness is level-triggered: it is recomputed from scratch on
+	/// every wakeup rather than cached, so a connection that is still ready after a
+	/// previous `poll` call will simply be reported again.
+	pub fn poll(
+		&self,
+		interest: &[(Quad, Available)],
+		timeout: Option<Duration>,
+	) -> io::Result<Vec<(Quad, Available)>> {
+		let ih = self.ih.as_ref().unwrap();
+		let deadline = timeout.map(|t| std::time::Instant::now() + t);
+		let mut cm = ih.manager.lock().unwrap();
+
+		loop {
+			let ready: Vec<(Quad, Available)> = interest
+				.iter()
+				.filter_map(|&(quad, want)| {
+					let avail = cm.connections.get(&quad)?.availability() & want;
+					if avail.is_empty() {
+						None
+					} else {
+						Some((quad, avail))
+					}
+				})
+				.collect();
+
+			if !ready.is_empty() {
+				return Ok(ready);
+			}
+
+			cm = match deadline {
+				Some(deadline) => {
+					let now = std::time::Instant::now();
+					if now >= deadline {
+						return Ok(Vec::new());
+					}
+					let (guard, timeout_result) =
+						ih.poll_var.wait_timeout(cm, deadline - now).unwrap();
+					if timeout_result.timed_out() {
+						return Ok(Vec::new());
+					}
+					guard
+				}
+				None => ih.poll_var.wait(cm).unwrap(),
+			};
+		}
+	}
+
+	/// Active open: allocate an ephemeral source port and dial `dst`. The SYN is sent
+	/// from `packet_loop`'s next tick, same as `accept`'s replies are sent from there.
+	pub fn connect(&mut self, dst: (Ipv4Addr, u16)) -> io::Result<TcpStream> {
+		let ih = self.ih.as_mut().unwrap();
+		let mut cm = ih.manager.lock().unwrap();
+
+		let port = (49152..=65535)
+			.find(|port| !cm.connections.keys().any(|q| q.destination.1 == *port))
+			.ok_or_else(|| {
+				io::Error::new(io::ErrorKind::AddrNotAvailable, "no ephemeral ports available")
+			})?;
+
+		// Keyed the way `packet_loop` keys incoming segments: the peer is the source.
+		let quad = Quad {
+			source: dst,
+			destination: (LOCAL_ADDR, port),
+		};
+
+		cm.connections.insert(quad, tcp::Connection::connect(quad.destination, quad.source));
+
+		drop(cm);
+		Ok(TcpStream {
+			quad,
+			h: ih.clone(),
+			nonblocking: false,
+			read_timeout: None,
+			write_timeout: None,
+		})
+	}
 }
 
 pub struct TcpListener {
@@ -205,6 +329,9 @@ impl TcpListener {
 				return Ok(TcpStream{
 					quad,
 					h: self.h.clone(),
+					nonblocking: false,
+					read_timeout: None,
+					write_timeout: None,
 				});
 			}
 			cm = self.h.pending_var.wait(cm).unwrap();
@@ -215,6 +342,19 @@ impl TcpListener {
 pub struct TcpStream {
 	quad: Quad,
 	h: InterfaceHandle,
+	nonblocking: bool,
+	read_timeout: Option<Duration>,
+	write_timeout: Option<Duration>,
+}
+
+/// Per-connection byte/ACK counters and the current smoothed RTT, from `TcpStream::stats`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+	pub bytes_accepted: u64,
+	pub bytes_sent: u64,
+	pub bytes_retransmitted: u64,
+	pub duplicate_acks: u64,
+	pub srtt: Option<Duration>,
 }
 
 impl Drop for TcpStream {
@@ -226,14 +366,13 @@ impl Drop for TcpStream {
 impl Read for TcpStream {
 	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
 		let mut cm = self.h.manager.lock().unwrap();
+		let deadline = self.read_timeout.map(|t| Instant::now() + t);
 
 		loop {
-			let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-				io::Error::new(
-					io::ErrorKind::ConnectionAborted,
-				"stream was terminated unexpectedly",
-				)
-			})?;
+			let c = match cm.connections.get_mut(&self.quad) {
+				Some(c) => c,
+				None => return Err(terminated_error(&mut cm, &self.quad)),
+			};
 
 			if c.is_rcv_closed() && c.incoming.is_empty() {
 				return Ok(0);
@@ -252,7 +391,37 @@ impl Read for TcpStream {
 				return Ok(nread);
 			}
 
-			cm =self.h.recieving_var.wait(cm).unwrap();
+			if self.nonblocking {
+				return Err(io::Error::new(
+					io::ErrorKind::WouldBlock,
+					"no data available yet",
+				));
+			}
+
+			cm = match deadline {
+				Some(deadline) => {
+					let now = Instant::now();
+					if now >= deadline {
+						return Err(io::Error::new(
+							io::ErrorKind::TimedOut,
+							"read timed out",
+						));
+					}
+					let (guard, timeout_result) = self
+						.h
+						.recieving_var
+						.wait_timeout(cm, deadline - now)
+						.unwrap();
+					if timeout_result.timed_out() {
+						return Err(io::Error::new(
+							io::ErrorKind::TimedOut,
+							"read timed out",
+						));
+					}
+					guard
+				}
+				None => self.h.recieving_var.wait(cm).unwrap(),
+			};
 		}
 	}
 }
@@ -260,34 +429,62 @@ impl Read for TcpStream {
 impl Write for TcpStream {
 	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
 		let mut cm = self.h.manager.lock().unwrap();
-		let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-			io::Error::new(
-				io::ErrorKind::ConnectionAborted,
-				"stream was terminated unexpectedly",
-			)
-		})?;
-
-		if c.unacked.len() >= SENDQUEUE_SIZE {
-			return Err(io::Error::new(
-				io::ErrorKind::WouldBlock,
-				"excessive bytes buffered",
-			));
-		}
+		let deadline = self.write_timeout.map(|t| Instant::now() + t);
+
+		loop {
+			let c = match cm.connections.get_mut(&self.quad) {
+				Some(c) => c,
+				None => return Err(terminated_error(&mut cm, &self.quad)),
+			};
+
+			if c.unacked.len() < SENDQUEUE_SIZE {
+				let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
+				c.unacked.extend(buf[..nwrite].iter());
+				c.stats.bytes_accepted += nwrite as u64;
 
-		let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
-		c.unacked.extend(buf[..nwrite].iter());
+				return Ok(nwrite);
+			}
+
+			if self.nonblocking {
+				return Err(io::Error::new(
+					io::ErrorKind::WouldBlock,
+					"excessive bytes buffered",
+				));
+			}
 
-		Ok(nwrite)
+			cm = match deadline {
+				Some(deadline) => {
+					let now = Instant::now();
+					if now >= deadline {
+						return Err(io::Error::new(
+							io::ErrorKind::TimedOut,
+							"write timed out",
+						));
+					}
+					let (guard, timeout_result) = self
+						.h
+						.pending_var
+						.wait_timeout(cm, deadline - now)
+						.unwrap();
+					if timeout_result.timed_out() {
+						return Err(io::Error::new(
+							io::ErrorKind::TimedOut,
+							"write timed out",
+						));
+					}
+					guard
+				}
+				None => self.h.pending_var.wait(cm).unwrap(),
+			};
+		}
 	}
 
 	fn flush(&mut self) -> io::Result<()> {
 		let mut cm = self.h.manager.lock().unwrap();
-		let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-			io::Error::new(
-				io::ErrorKind::ConnectionAborted,
-				"stream was terminated unexpectedly",
-			)
-		})?;
+		let c = match cm.connections.get_mut(&self.quad) {
+			Some(c) => c,
+			None => return Err(terminated_error(&mut cm, &self.quad)),
+		};
 
 		if c.unacked.is_empty() {
 			Ok(())
@@ -301,14 +498,68 @@ impl Write for TcpStream {
 }
 
 impl TcpStream {
+	/// The `Quad` identifying this stream's connection, for use with `Interface::poll`.
+	pub fn quad(&self) -> Quad {
+		self.quad
+	}
+
+	/// When set, `read` returns `ErrorKind::WouldBlock` instead of parking on the
+	/// receive condvar if no data is available yet, and `write` returns it instead of
+	/// parking on the pending condvar if the send queue is full.
+	pub fn set_nonblocking(&mut self, nonblocking: bool) {
+		self.nonblocking = nonblocking;
+	}
+
+	/// When set, `read` gives up and returns `ErrorKind::TimedOut` after waiting this long
+	/// for data, instead of blocking on the receive condvar indefinitely.
+	pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+		self.read_timeout = timeout;
+	}
+
+	/// When set, `write` gives up and returns `ErrorKind::TimedOut` after waiting this long
+	/// for send-queue space to free up, instead of blocking on the pending condvar
+	/// indefinitely.
+	pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+		self.write_timeout = timeout;
+	}
+
+	/// A snapshot of this connection's byte/ACK counters and current smoothed RTT.
+	pub fn stats(&self) -> io::Result<ConnectionStats> {
+		let mut cm = self.h.manager.lock().unwrap();
+		let c = match cm.connections.get(&self.quad) {
+			Some(c) => c,
+			None => return Err(terminated_error(&mut cm, &self.quad)),
+		};
+
+		let s = c.stats();
+		Ok(ConnectionStats {
+			bytes_accepted: s.bytes_accepted,
+			bytes_sent: s.bytes_sent,
+			bytes_retransmitted: s.bytes_retransmitted,
+			duplicate_acks: s.duplicate_acks,
+			srtt: s.srtt,
+		})
+	}
+
+	/// Caps egress to `bytes_per_sec` via a token bucket that `on_tick` consults before
+	/// sending; pass `None` to remove the cap.
+	pub fn set_send_rate(&mut self, bytes_per_sec: Option<u32>) -> io::Result<()> {
+		let mut cm = self.h.manager.lock().unwrap();
+		let c = match cm.connections.get_mut(&self.quad) {
+			Some(c) => c,
+			None => return Err(terminated_error(&mut cm, &self.quad)),
+		};
+
+		c.set_send_rate(bytes_per_sec);
+		Ok(())
+	}
+
 	pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
 		let mut cm = self.h.manager.lock().unwrap();
-		let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-			io::Error::new(
-				io::ErrorKind::ConnectionAborted,
-				"stream was terminated unexpectedly",
-			)
-		})?;
+		let c = match cm.connections.get_mut(&self.quad) {
+			Some(c) => c,
+			None => return Err(terminated_error(&mut cm, &self.quad)),
+		};
 
 		c.close()
 	}
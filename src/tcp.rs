@@ -1,34 +1,61 @@
 use bitflags::bitflags;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::net::Ipv4Addr;
 use std::{io, time};
 use etherparse::{Ipv4Header, TcpHeader, Ipv4HeaderSlice, TcpHeaderSlice, IpTrafficClass};
 use tun_tap::Iface;
 
 //Spec: RFC 793: https://datatracker.ietf.org/doc/html/rfc793
 
+/// Maximum segment lifetime. TIME-WAIT holds the connection open for 2*MSL so that
+/// delayed duplicates from the old connection can't be mistaken for a new one.
+const MSL: time::Duration = time::Duration::from_secs(2 * 60);
+
 bitflags! {
-	pub(crate) struct Available: u8 {
+	pub struct Available: u8 {
 		const READ = 0b00000001;
 		const WRITE = 0b00000010;
 	}
 }
 
+/// The NIC-facing half of sending a segment, abstracted so that `Connection`'s state
+/// machine can be driven with synthetic segments in tests without a real TUN device.
+pub(crate) trait NicIo {
+	fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+}
+
+impl NicIo for Iface {
+	fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+		Iface::send(self, buf)
+	}
+}
+
 #[derive(Debug)]
 enum State {
-	//Closed,
+	Closed,
 	//Listen,
 	SynRecvd,
+	SynSent,
 	Estab,
 	FinWait1,
 	FinWait2,
+	Closing,
 	TimeWait,
+	CloseWait,
+	LastAck,
 }
 
 impl State {
 	fn is_synchronized(&self) -> bool {
 		match *self {
-			State::SynRecvd => false,
-			State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+			State::Closed | State::SynRecvd | State::SynSent => false,
+			State::Estab
+			| State::FinWait1
+			| State::FinWait2
+			| State::Closing
+			| State::TimeWait
+			| State::CloseWait
+			| State::LastAck => true,
 		}
 	}
 }
@@ -52,7 +79,144 @@ struct RecvSequenceSpace {
 
 struct Timers {
 	send_times: BTreeMap<u32, time::Instant>,
-	srtt: f64,
+	/// Sequence numbers that have been resent at least once since they were first sent,
+	/// per Karn's algorithm - an ACK that prunes one of these can't be used as an RTT
+	/// sample since we can't tell which transmission it's acking.
+	retransmitted: BTreeSet<u32>,
+	srtt: Option<f64>,
+	rttvar: f64,
+	rto: time::Duration,
+}
+
+impl Timers {
+	const ALPHA: f64 = 1.0 / 8.0;
+	const BETA: f64 = 1.0 / 4.0;
+	/// Clock granularity (RFC 6298's `G`); our tick interval is a good stand-in.
+	const CLOCK_GRANULARITY: f64 = 0.01;
+	const MIN_RTO: time::Duration = time::Duration::from_secs(1);
+	const MAX_RTO: time::Duration = time::Duration::from_secs(60);
+
+	/// Jacobson/Karn RTO estimation (RFC 6298): on the first sample, seed `srtt`/`rttvar`
+	/// directly from it; afterwards, exponentially smooth both.
+	fn sample_rtt(&mut self, r: time::Duration) {
+		let r = r.as_secs_f64();
+		self.rttvar = match self.srtt {
+			None => r / 2.0,
+			Some(srtt) => (1.0 - Self::BETA) * self.rttvar + Self::BETA * (srtt - r).abs(),
+		};
+		self.srtt = Some(match self.srtt {
+			None => r,
+			Some(srtt) => (1.0 - Self::ALPHA) * srtt + Self::ALPHA * r,
+		});
+
+		let rto = self.srtt.unwrap() + Self::CLOCK_GRANULARITY.max(4.0 * self.rttvar);
+		self.rto = time::Duration::from_secs_f64(rto).clamp(Self::MIN_RTO, Self::MAX_RTO);
+	}
+
+	/// Exponential backoff applied on every retransmit timeout, until the next RTT
+	/// sample resets `rto` to the Jacobson/Karn estimate.
+	fn backoff(&mut self) {
+		self.rto = (self.rto * 2).min(Self::MAX_RTO);
+	}
+}
+
+/// TCP Reno: slow start, congestion avoidance, and fast retransmit/fast recovery.
+struct Congestion {
+	cwnd: u32,
+	ssthresh: u32,
+	dup_acks: u8,
+}
+
+impl Congestion {
+	/// Sender maximum segment size; a fixed stand-in since we don't negotiate MSS.
+	const SMSS: u32 = 536;
+
+	fn new() -> Self {
+		Congestion {
+			cwnd: Self::SMSS,
+			ssthresh: u32::MAX,
+			dup_acks: 0,
+		}
+	}
+
+	/// A new ACK covering previously-unacked data arrived: grow the window and, if a
+	/// fast recovery was in progress, leave it.
+	fn on_new_ack(&mut self) {
+		if self.dup_acks >= 3 {
+			self.cwnd = self.ssthresh;
+		} else if self.cwnd < self.ssthresh {
+			self.cwnd += Self::SMSS;
+		} else {
+			self.cwnd += std::cmp::max(1, Self::SMSS * Self::SMSS / self.cwnd);
+		}
+		self.dup_acks = 0;
+	}
+
+	/// A duplicate ACK (same ack number, no new data) arrived. Returns `true` on the
+	/// third one, when the caller should retransmit the presumed-lost segment.
+	fn on_dup_ack(&mut self, flight: u32) -> bool {
+		self.dup_acks += 1;
+		match self.dup_acks {
+			3 => {
+				self.ssthresh = std::cmp::max(flight / 2, 2 * Self::SMSS);
+				self.cwnd = self.ssthresh + 3 * Self::SMSS;
+				true
+			}
+			n if n > 3 => {
+				self.cwnd += Self::SMSS;
+				false
+			}
+			_ => false,
+		}
+	}
+
+	/// A retransmission timeout fired: collapse back to slow start.
+	fn on_rto(&mut self, flight: u32) {
+		self.ssthresh = std::cmp::max(flight / 2, 2 * Self::SMSS);
+		self.cwnd = Self::SMSS;
+		self.dup_acks = 0;
+	}
+}
+
+/// Caps the payload bytes `on_tick` may send per tick to `rate` bytes/sec, with bursts
+/// up to one second's worth of tokens.
+struct TokenBucket {
+	rate: f64,
+	tokens: f64,
+	last_refill: time::Instant,
+}
+
+impl TokenBucket {
+	fn new(bytes_per_sec: u32) -> Self {
+		TokenBucket {
+			rate: bytes_per_sec as f64,
+			tokens: bytes_per_sec as f64,
+			last_refill: time::Instant::now(),
+		}
+	}
+
+	/// Refill since the last call, then hand out up to `want` bytes worth of tokens.
+	fn take(&mut self, want: u32) -> u32 {
+		let now = time::Instant::now();
+		let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+		self.tokens = (self.tokens + self.rate * elapsed).min(self.rate);
+		self.last_refill = now;
+
+		let available = self.tokens as u32;
+		let take = std::cmp::min(want, available);
+		self.tokens -= take as f64;
+		take
+	}
+}
+
+/// Per-connection counters surfaced through `TcpStream::stats`.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Stats {
+	pub(crate) bytes_accepted: u64,
+	pub(crate) bytes_sent: u64,
+	pub(crate) bytes_retransmitted: u64,
+	pub(crate) duplicate_acks: u64,
+	pub(crate) srtt: Option<time::Duration>,
 }
 
 pub struct Connection {
@@ -62,33 +226,65 @@ pub struct Connection {
 	ip: Ipv4Header,
 	tcp: TcpHeader,
 	timers: Timers,
+	congestion: Congestion,
+	rate_limiter: Option<TokenBucket>,
+	pub(crate) stats: Stats,
 	pub(crate) incoming: VecDeque<u8>,
 	pub(crate) unacked: VecDeque<u8>,
 	pub(crate) closed: bool,
 	closed_at: Option<u32>,
+	time_wait_at: Option<time::Instant>,
+	pub(crate) refused: bool,
 }
 
 impl Connection {
-	pub(crate) fn is_rcv_closed(&self) -> bool {
-		if let State::TimeWait = self.state {
-			true
-		} else {
-			false
+	pub(crate) fn stats(&self) -> Stats {
+		Stats {
+			srtt: self.timers.srtt.map(time::Duration::from_secs_f64),
+			..self.stats
+		}
+	}
+
+	pub(crate) fn set_send_rate(&mut self, bytes_per_sec: Option<u32>) {
+		self.rate_limiter = bytes_per_sec.map(TokenBucket::new);
+	}
+
+	/// Applies the token bucket (if any) to a payload size we'd otherwise send.
+	fn take_tokens(&mut self, want: u32) -> u32 {
+		match &mut self.rate_limiter {
+			Some(bucket) => bucket.take(want),
+			None => want,
 		}
 	}
 
-	fn availability(&self) -> Available {
+	pub(crate) fn is_rcv_closed(&self) -> bool {
+		matches!(
+			self.state,
+			State::CloseWait | State::LastAck | State::Closing | State::TimeWait | State::Closed
+		)
+	}
+
+	/// Whether this connection has reached CLOSED and can be dropped from
+	/// `ConnectionManager.connections`.
+	pub(crate) fn is_done(&self) -> bool {
+		matches!(self.state, State::Closed)
+	}
+
+	pub(crate) fn availability(&self) -> Available {
 		let mut a = Available::empty();
 		if self.is_rcv_closed() || !self.incoming.is_empty() {
 			a |= Available::READ;
 		}
+		if self.unacked.len() < crate::SENDQUEUE_SIZE {
+			a |= Available::WRITE;
+		}
 
 		a
 	}
 }
 
 impl Connection {
-	pub fn accept<'a>(nic: &mut Iface, iph: Ipv4HeaderSlice<'a>, tcph: TcpHeaderSlice<'a>, data:
+	pub fn accept<'a, N: NicIo>(nic: &mut N, iph: Ipv4HeaderSlice<'a>, tcph: TcpHeaderSlice<'a>, data:
 	&'a [u8], ) -> io::Result<Option<Self>> {
 		let buf = [0u8; 1504];
 		if !tcph.syn() {
@@ -100,8 +296,14 @@ impl Connection {
 		let mut c = Connection {
 			timers: Timers {
 				send_times: Default::default(),
-				srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
+				retransmitted: Default::default(),
+				srtt: None,
+				rttvar: 0.0,
+				rto: Timers::MIN_RTO,
 			},
+			congestion: Congestion::new(),
+			rate_limiter: None,
+			stats: Stats::default(),
 			state: State::SynRecvd,
 			send: SendSequenceSpace {
 				iss,
@@ -140,6 +342,8 @@ impl Connection {
 			unacked: Default::default(),
 			closed: false,
 			closed_at: None,
+			time_wait_at: None,
+			refused: false,
 		};
 
 		c.tcp.syn = true;
@@ -148,7 +352,58 @@ impl Connection {
 		Ok(Some(c))
 	}
 
-	fn write(&mut self, nic: &mut Iface, seq: u32, mut limit: usize) -> io::Result<usize> {
+	/// Active open: build a connection in `SynSent` for `Interface::connect`. The SYN
+	/// itself isn't written here since the caller doesn't hold the `nic` - it goes out
+	/// on the connection's first `on_tick`, the same way a retransmit would.
+	pub fn connect(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> Self {
+		let iss = 0;
+		let wnd = 1024;
+		let mut c = Connection {
+			timers: Timers {
+				send_times: Default::default(),
+				retransmitted: Default::default(),
+				srtt: None,
+				rttvar: 0.0,
+				rto: Timers::MIN_RTO,
+			},
+			congestion: Congestion::new(),
+			rate_limiter: None,
+			stats: Stats::default(),
+			state: State::SynSent,
+			send: SendSequenceSpace {
+				iss,
+				una: iss,
+				nxt: iss,
+				wnd,
+				up: false,
+				wl1: 0,
+				wl2: 0,
+			},
+			recv: RecvSequenceSpace {
+				irs: 0,
+				nxt: 0,
+				wnd: 1024,
+				up: false,
+			},
+			tcp: TcpHeader::new(src.1, dst.1, iss, wnd),
+			ip: Ipv4Header::new(
+				0, 64, IpTrafficClass::Tcp,
+				src.0.octets(),
+				dst.0.octets(),
+			),
+			incoming: Default::default(),
+			unacked: Default::default(),
+			closed: false,
+			closed_at: None,
+			time_wait_at: None,
+			refused: false,
+		};
+
+		c.tcp.syn = true;
+		c
+	}
+
+	fn write<N: NicIo>(&mut self, nic: &mut N, seq: u32, mut limit: usize) -> io::Result<usize> {
 		let mut buf = [0u8; 1500];
 		self.tcp.sequence_number = seq;
 		self.tcp.acknowledgment_number = self.recv.nxt;
@@ -224,12 +479,13 @@ impl Connection {
 			self.send.nxt = next_seq;
 		}
 		self.timers.send_times.insert(seq, time::Instant::now());
+		self.stats.bytes_sent += payload_bytes as u64;
 
 		nic.send(&buf[..payload_ends_at])?;
 		Ok(payload_bytes)
 	}
 
-	fn send_rst(&mut self, nic: &mut Iface) -> io::Result<()> {
+	fn send_rst<N: NicIo>(&mut self, nic: &mut N) -> io::Result<()> {
 		self.tcp.rst = true;
 
 		self.tcp.sequence_number = 0;
@@ -238,8 +494,17 @@ impl Connection {
 		Ok(())
 	}
 
-	pub(crate) fn on_tick(&mut self, nic: &mut Iface) -> io::Result<()> {
-		if let State:: FinWait2 | State::TimeWait = self.state {
+	pub(crate) fn on_tick<N: NicIo>(&mut self, nic: &mut N) -> io::Result<()> {
+		if let State::TimeWait = self.state {
+			if let Some(time_wait_at) = self.time_wait_at {
+				if time_wait_at.elapsed() >= MSL * 2 {
+					self.state = State::Closed;
+				}
+			}
+			return Ok(());
+		}
+
+		if let State::Closed | State::FinWait2 = self.state {
 			return Ok(());
 		}
 
@@ -249,32 +514,62 @@ impl Connection {
 		let waited_for = self.timers.send_times.range(self.send.una..).next().map(|t| t.1.elapsed
 		());
 
-		let should_retransmit = if let Some(waited_for) = waited_for {
-			waited_for > time::Duration::from_secs(1)
-			&& waited_for.as_secs_f64() > 1.5 * self.timers.srtt
-		} else {
-			false
-		};
+		let should_retransmit = waited_for.is_some_and(|waited_for| waited_for > self.timers.rto);
 
 		if should_retransmit {
-			let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-			if resend < self.send.wnd as u32 && self.closed {
+			let win = std::cmp::min(self.send.wnd as u32, self.congestion.cwnd);
+			let resend = std::cmp::min(self.unacked.len() as u32, win);
+			let resend = self.take_tokens(resend);
+			if resend == 0 && self.unacked.len() as u32 > 0 {
+				// Rate limiter is empty this tick; wait for it to refill rather than
+				// sending nothing and still counting it as a retransmit. A bare control
+				// segment (SYN/FIN with no payload) has no bytes to rate-limit, so it
+				// always gets retransmitted below.
+				return Ok(());
+			}
+
+			self.congestion.on_rto(nunacked_data);
+
+			if let State::SynSent = self.state {
+				self.tcp.syn = true;
+			}
+
+			if resend < win && self.closed {
 				self.tcp.fin = true;
 				self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
 			}
 
-			self.write(nic, self.send.una, resend as usize)?;
+			let resend_end = self.send.una.wrapping_add(resend);
+			let resent: Vec<u32> = self
+				.timers
+				.send_times
+				.range(self.send.una..resend_end)
+				.map(|(&seq, _)| seq)
+				.collect();
+			self.timers.retransmitted.extend(resent);
+			self.timers.backoff();
+
+			let n = self.write(nic, self.send.una, resend as usize)?;
+			self.stats.bytes_retransmitted += n as u64;
 		} else {
 			if nunsent_data == 0 && self.closed_at.is_some() {
 				return Ok(());
 			}
 
-			let allowed = self.send.wnd as u32 - nunacked_data;
+			let win = std::cmp::min(self.send.wnd as u32, self.congestion.cwnd);
+			let allowed = win.saturating_sub(nunacked_data);
 			if allowed == 0 {
 				return Ok(());
 			}
 
 			let send = std::cmp::min(nunsent_data, allowed);
+			let send = self.take_tokens(send);
+			if send == 0 && nunsent_data > 0 {
+				// Nothing to send this tick because the rate limiter is empty; defer
+				// to a later tick instead of sending an empty segment.
+				return Ok(());
+			}
+
 			if send < allowed && self.closed && self.closed_at.is_none() {
 				self.tcp.fin = true;
 				self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
@@ -286,13 +581,65 @@ impl Connection {
 		Ok(())
 	}
 
-	pub(crate) fn on_packet<'a>(
+	pub(crate) fn on_packet<'a, N: NicIo>(
 		&mut self,
-		nic: &mut Iface,
+		nic: &mut N,
 		iph: Ipv4HeaderSlice<'a>,
 		tcph: TcpHeaderSlice<'a>,
 		data: &'a [u8],
 	) -> io::Result<Available> {
+		if let State::SynSent = self.state {
+			// RFC 793 SYN-SENT processing happens before the generic segment
+			// acceptability test below, since recv.nxt/irs aren't established yet.
+			if tcph.rst() {
+				// The peer refused the connection (e.g. closed or firewalled port);
+				// fail fast instead of retrying SYNs forever.
+				self.refused = true;
+				self.state = State::Closed;
+				return Ok(self.availability());
+			}
+
+			if tcph.ack() {
+				let ackn = tcph.acknowledgment_number();
+				if !is_between_wrapped(
+					self.send.una.wrapping_sub(1),
+					ackn,
+					self.send.nxt.wrapping_add(1),
+				) {
+					self.send_rst(nic)?;
+					return Ok(self.availability());
+				}
+
+				if !tcph.syn() {
+					return Ok(self.availability());
+				}
+
+				self.recv.irs = tcph.sequence_number();
+				self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+				self.recv.wnd = tcph.window_size();
+				self.send.una = ackn;
+				self.state = State::Estab;
+				self.tcp.ack = true;
+				self.write(nic, self.send.nxt, 0)?;
+				return Ok(self.availability());
+			}
+
+			if tcph.syn() {
+				// Simultaneous open: both sides sent a bare SYN. Adopt the peer's
+				// ISS and resend ours, now with ACK set, from SynRecvd.
+				self.recv.irs = tcph.sequence_number();
+				self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+				self.recv.wnd = tcph.window_size();
+				self.state = State::SynRecvd;
+				self.tcp.syn = true;
+				self.tcp.ack = true;
+				self.write(nic, self.send.iss, 0)?;
+				return Ok(self.availability());
+			}
+
+			return Ok(self.availability());
+		}
+
 		let seqn = tcph.sequence_number();
 		let mut slen = data.len() as u32;
 		if tcph.fin() {
@@ -354,7 +701,13 @@ impl Connection {
 			}
 		}
 
-		if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+		if let State::Estab
+		| State::FinWait1
+		| State::FinWait2
+		| State::CloseWait
+		| State::Closing
+		| State::LastAck = self.state
+		{
 			if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
 				println!("ack for {} (last: {}); prune in {:?}", ackn, self.send.una, self.unacked);
 				if !self.unacked.is_empty() {
@@ -370,24 +723,65 @@ impl Connection {
 					let old = std::mem::replace(&mut self.timers.send_times, BTreeMap::new());
 
 					let una = self.send.una;
-					let mut srtt = &mut self.timers.srtt;
+					let retransmitted = &mut self.timers.retransmitted;
+					let mut sample = None;
 					self.timers.send_times.extend(old.into_iter().filter_map(|(seq, sent)| {
 						if is_between_wrapped(una, seq, ackn) {
-							*srtt = 0.8 * *srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
+							// Karn's algorithm: a segment that was retransmitted can't
+							// tell us which transmission this ACK is for, so it can't
+							// be used as an RTT sample.
+							if !retransmitted.remove(&seq) {
+								sample.get_or_insert(sent.elapsed());
+							}
 							None
 						} else {
 							Some((seq, sent))
 						}
 					}));
+
+					if let Some(sample) = sample {
+						self.timers.sample_rtt(sample);
+					}
 				}
 				self.send.una = ackn;
+				self.congestion.on_new_ack();
+			} else if data.is_empty() && ackn == self.send.una && self.send.una != self.send.nxt {
+				// Duplicate ACK: same ack number as last time, no new data, and we
+				// still have unacked data outstanding.
+				self.stats.duplicate_acks += 1;
+				let flight = self.send.nxt.wrapping_sub(self.send.una);
+				if self.congestion.on_dup_ack(flight) {
+					let resend = std::cmp::min(self.unacked.len() as u32, flight);
+					let resend = self.take_tokens(resend);
+					if resend > 0 || self.unacked.len() == 0 {
+						let resend_end = self.send.una.wrapping_add(resend);
+						let resent: Vec<u32> = self
+							.timers
+							.send_times
+							.range(self.send.una..resend_end)
+							.map(|(&seq, _)| seq)
+							.collect();
+						self.timers.retransmitted.extend(resent);
+
+						let n = self.write(nic, self.send.una, resend as usize)?;
+						self.stats.bytes_retransmitted += n as u64;
+					}
+				}
 			}
 		}
 
-		if let State::FinWait1 = self.state {
+		if let State::FinWait1 | State::Closing | State::LastAck = self.state {
 			if let Some(closed_at) = self.closed_at {
 				if self.send.una == closed_at.wrapping_add(1) {
-					self.state = State::FinWait2;
+					self.state = match self.state {
+						State::FinWait1 => State::FinWait2,
+						State::Closing => {
+							self.time_wait_at = Some(time::Instant::now());
+							State::TimeWait
+						}
+						State::LastAck => State::Closed,
+						_ => unreachable!(),
+					};
 				}
 			}
 		}
@@ -408,11 +802,29 @@ impl Connection {
 
 		if tcph.fin() {
 			match self.state {
+				State::Estab => {
+					self.recv.nxt = self.recv.nxt.wrapping_add(1);
+					self.write(nic, self.send.nxt, 0)?;
+					self.state = State::CloseWait;
+				},
+				State::FinWait1 => {
+					// Simultaneous close: the peer's FIN arrived before the ACK of
+					// ours did.
+					self.recv.nxt = self.recv.nxt.wrapping_add(1);
+					self.write(nic, self.send.nxt, 0)?;
+					self.state = State::Closing;
+				},
 				State::FinWait2 => {
 					self.recv.nxt = self.recv.nxt.wrapping_add(1);
 					self.write(nic, self.send.nxt, 0)?;
+					self.time_wait_at = Some(time::Instant::now());
 					self.state = State::TimeWait;
 				},
+				// A retransmitted FIN in a state that already accounted for it; just
+				// re-ack it.
+				State::CloseWait | State::LastAck | State::Closing | State::TimeWait => {
+					self.write(nic, self.send.nxt, 0)?;
+				},
 				_ => unimplemented!(),
 			}
 		}
@@ -426,7 +838,10 @@ impl Connection {
 			State::SynRecvd | State::Estab => {
 				self.state = State::FinWait1;
 			},
-			State::FinWait1 | State::FinWait2 => {},
+			State::CloseWait => {
+				self.state = State::LastAck;
+			},
+			State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck => {},
 			_ => {
 				return Err(io::Error::new(
 					io::ErrorKind::NotConnected,
@@ -444,4 +859,183 @@ fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
 
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
 	wrapping_lt(start, x) && wrapping_lt(x, end)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A `NicIo` that just records what would have gone out, so `Connection`'s state
+	/// machine can be driven with synthetic segments without a real TUN device.
+	struct FakeNic {
+		sent: Vec<Vec<u8>>,
+	}
+
+	impl NicIo for FakeNic {
+		fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.sent.push(buf.to_vec());
+			Ok(buf.len())
+		}
+	}
+
+	/// Builds a raw IPv4+TCP segment, as if it arrived from `src` addressed to `dst`.
+	fn synthetic_segment(
+		src: (Ipv4Addr, u16),
+		dst: (Ipv4Addr, u16),
+		seq: u32,
+		ack: u32,
+		(syn, ack_flag, fin): (bool, bool, bool),
+		wnd: u16,
+		payload: &[u8],
+	) -> Vec<u8> {
+		let mut ip = Ipv4Header::new(0, 64, IpTrafficClass::Tcp, src.0.octets(), dst.0.octets());
+		let mut tcp = TcpHeader::new(src.1, dst.1, seq, wnd);
+		tcp.acknowledgment_number = ack;
+		tcp.syn = syn;
+		tcp.ack = ack_flag;
+		tcp.fin = fin;
+
+		ip.set_payload_len(tcp.header_len() as usize + payload.len());
+		tcp.checksum = tcp
+			.calc_checksum_ipv4(&ip, payload)
+			.expect("failed to compute checksum");
+
+		let mut buf = Vec::new();
+		ip.write(&mut buf).unwrap();
+		tcp.write(&mut buf).unwrap();
+		buf.extend_from_slice(payload);
+		buf
+	}
+
+	fn parse<'a>(buf: &'a [u8]) -> (Ipv4HeaderSlice<'a>, TcpHeaderSlice<'a>) {
+		let iph = Ipv4HeaderSlice::from_slice(buf).unwrap();
+		let tcph = TcpHeaderSlice::from_slice(&buf[iph.slice().len()..]).unwrap();
+		(iph, tcph)
+	}
+
+	const US: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 0, 1), 1000);
+	const PEER: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 0, 2), 80);
+
+	/// A bare-bones established connection, as if the handshake already happened.
+	fn established() -> Connection {
+		let mut c = Connection::connect(US, PEER);
+		c.state = State::Estab;
+		c.recv.irs = 500;
+		c.recv.nxt = 500;
+		c.recv.wnd = 1024;
+		c.send.una = 0;
+		c.send.nxt = 0;
+		c.send.wnd = 1024;
+		c.tcp.syn = false;
+		c
+	}
+
+	#[test]
+	fn passive_close_reaches_closed() {
+		let mut c = established();
+		let mut nic = FakeNic { sent: Vec::new() };
+
+		let fin = synthetic_segment(PEER, US, 500, 0, (false, true, true), 1024, &[]);
+		let (iph, tcph) = parse(&fin);
+		c.on_packet(&mut nic, iph, tcph, &[]).unwrap();
+		assert!(matches!(c.state, State::CloseWait));
+		assert_eq!(c.recv.nxt, 501);
+
+		c.close().unwrap();
+		assert!(matches!(c.state, State::LastAck));
+
+		c.on_tick(&mut nic).unwrap();
+		assert_eq!(c.closed_at, Some(0));
+		assert_eq!(c.send.nxt, 1);
+
+		let ack = synthetic_segment(PEER, US, 501, 1, (false, true, false), 1024, &[]);
+		let (iph, tcph) = parse(&ack);
+		c.on_packet(&mut nic, iph, tcph, &[]).unwrap();
+		assert!(c.is_done());
+	}
+
+	#[test]
+	fn simultaneous_close_reaches_time_wait_then_closed() {
+		let mut c = established();
+		let mut nic = FakeNic { sent: Vec::new() };
+
+		c.close().unwrap();
+		assert!(matches!(c.state, State::FinWait1));
+		c.on_tick(&mut nic).unwrap();
+		assert_eq!(c.closed_at, Some(0));
+
+		let fin = synthetic_segment(PEER, US, 500, 0, (false, true, true), 1024, &[]);
+		let (iph, tcph) = parse(&fin);
+		c.on_packet(&mut nic, iph, tcph, &[]).unwrap();
+		assert!(matches!(c.state, State::Closing));
+		assert_eq!(c.recv.nxt, 501);
+
+		let ack = synthetic_segment(PEER, US, 501, 1, (false, true, false), 1024, &[]);
+		let (iph, tcph) = parse(&ack);
+		c.on_packet(&mut nic, iph, tcph, &[]).unwrap();
+		assert!(matches!(c.state, State::TimeWait));
+		assert!(c.time_wait_at.is_some());
+
+		c.time_wait_at = Some(time::Instant::now() - (MSL * 2 + time::Duration::from_secs(1)));
+		c.on_tick(&mut nic).unwrap();
+		assert!(c.is_done());
+	}
+
+	fn fresh_timers() -> Timers {
+		Timers {
+			send_times: Default::default(),
+			retransmitted: Default::default(),
+			srtt: None,
+			rttvar: 0.0,
+			rto: Timers::MIN_RTO,
+		}
+	}
+
+	#[test]
+	fn sample_rtt_seeds_then_smooths() {
+		let mut t = fresh_timers();
+
+		t.sample_rtt(time::Duration::from_millis(200));
+		assert_eq!(t.srtt, Some(0.2));
+		assert_eq!(t.rttvar, 0.1);
+
+		// A second sample equal to the first shouldn't move srtt, and should decay
+		// rttvar since there's no disagreement with the estimate.
+		t.sample_rtt(time::Duration::from_millis(200));
+		assert!((t.srtt.unwrap() - 0.2).abs() < 1e-9);
+		assert!(t.rttvar < 0.1);
+	}
+
+	#[test]
+	fn backoff_doubles_and_clamps_to_max_rto() {
+		let mut t = fresh_timers();
+		t.rto = Timers::MAX_RTO - time::Duration::from_secs(1);
+
+		t.backoff();
+		assert_eq!(t.rto, Timers::MAX_RTO);
+
+		t.backoff();
+		assert_eq!(t.rto, Timers::MAX_RTO);
+	}
+
+	#[test]
+	fn rto_retransmit_marks_sequence_via_karns_algorithm() {
+		let mut c = established();
+		let mut nic = FakeNic { sent: Vec::new() };
+		c.unacked.extend(b"data".iter().copied());
+
+		c.on_tick(&mut nic).unwrap();
+		assert_eq!(c.send.nxt, 4);
+		assert!(c.timers.send_times.contains_key(&0));
+		assert_eq!(c.stats.bytes_retransmitted, 0);
+
+		// Pretend the RTO fired: back-date the send so on_tick sees it as overdue.
+		c.timers
+			.send_times
+			.insert(0, time::Instant::now() - c.timers.rto - time::Duration::from_millis(1));
+		c.on_tick(&mut nic).unwrap();
+
+		assert!(c.timers.retransmitted.contains(&0));
+		assert_eq!(c.stats.bytes_retransmitted, 4);
+	}
 }
\ No newline at end of file